@@ -1,7 +1,9 @@
 mod noise_codec;
+mod noise_delimited_codec;
 mod pb;
 
-use noise_codec::{NOISE_PARAMS, NoiseCodec, NoiseStream};
+use noise_codec::{NOISE_PARAMS, NoiseCodec};
+use noise_delimited_codec::NoiseDelimitedCodec;
 use pb::{Request, Response};
 use tracing::info;
 
@@ -13,23 +15,24 @@ async fn main() -> Result<()> {
     tracing_subscriber::fmt::init();
     let addr = "127.0.0.1:8888";
     let stream = TcpStream::connect(addr).await?;
-    // 客户端和服务器端要保持同步
-    // 加密
-    let mut stream = NoiseCodec::builder(NOISE_PARAMS, true).new_framed(stream)?;
-
-    stream.handshake().await?;
+    // 作为 initiator 完成 Noise XX 握手，之后这条连接只收发类型化的
+    // Request/Response，服务端和客户端从此说的是同一种协议。同样从环境变量
+    // 读取可选的长期静态私钥（`KV_STATIC_KEY_FILE`）和可信服务端公钥白名单
+    // （`KV_PEER_PUBLIC_KEY_ALLOWLIST`），让客户端也能固定（pin）它连接的服务端。
+    let mut stream = NoiseDelimitedCodec::<Request, Response>::handshake_framed(
+        NoiseCodec::builder_from_env(NOISE_PARAMS, true)?,
+        stream,
+    )
+    .await?;
 
     let msg = Request::new_put("hello", b"world");
-    // 将请求序列化成字节缓冲区
-    stream.send(msg.into()).await?;
+    stream.send(msg).await?;
 
     let msg = Request::new_get("hello");
-    stream.send(msg.into()).await?;
+    stream.send(msg).await?;
 
     // 接收响应
-    while let Some(Ok(buf)) = stream.next().await {
-        // 反序列化响应
-        let resp = Response::try_from(buf)?;
+    while let Some(Ok(resp)) = stream.next().await {
         info!("Got a response {:?}", resp);
     }
 
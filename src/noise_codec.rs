@@ -10,9 +10,31 @@ use tracing::info;
 pub const NOISE_PARAMS: &str = "Noise_XX_25519_ChaChaPoly_BLAKE2s";
 const HEADER_LEN: usize = 2;
 const MAX_FRAME_SIZE: usize = 65535;
+// ChaChaPoly 的 AEAD tag 占 16 字节，单个 Noise transport 消息能携带的明文
+// 上限因此是 MAX_FRAME_SIZE - TAG_LEN。
+const TAG_LEN: usize = 16;
+const MAX_CHUNK_PLAINTEXT: usize = MAX_FRAME_SIZE - TAG_LEN;
+// 把一个十六进制字符串（`KV_PEER_PUBLIC_KEY_ALLOWLIST` 里的一项）解码成原始
+// 字节，用来配置 `remote_public_key_allowlist`。
+fn decode_hex(s: &str) -> Result<Vec<u8>> {
+    if !s.len().is_multiple_of(2) {
+        return Err(anyhow::anyhow!("hex key must have an even number of digits"));
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| {
+            u8::from_str_radix(&s[i..i + 2], 16)
+                .map_err(|e| anyhow::anyhow!("invalid hex digit in key: {e}"))
+        })
+        .collect()
+}
+
+#[derive(Clone)]
 pub struct Builder {
     params: &'static str,
     initiator: bool,
+    local_private_key: Option<Vec<u8>>,
+    remote_public_key_allowlist: Option<Vec<Vec<u8>>>,
 }
 
 enum NoiseState {
@@ -43,6 +65,13 @@ pub struct NoiseCodec {
     #[allow(dead_code)]
     builder: Builder,
     state: NoiseState,
+    // 对端静态公钥白名单：`switch_transport_mode` 里用它核验对端身份。
+    remote_public_key_allowlist: Option<Vec<Vec<u8>>>,
+    // 一条逻辑消息可能被拆成多个 Noise frame 发送；`reassembly_total_len`
+    // 保存从 varint 头部解出的明文总长度，`reassembly_buf` 累积已解密但
+    // 尚未凑满的明文，直到达到总长度才把完整消息交还给调用方。
+    reassembly_total_len: Option<u64>,
+    reassembly_buf: BytesMut,
 }
 
 impl NoiseCodec {
@@ -50,9 +79,48 @@ impl NoiseCodec {
         Builder::new(params, initiator)
     }
 
-    pub fn switch_transport_mode(&mut self) -> Result<(), snow::Error> {
+    /// 和 `builder` 一样，但额外从环境变量里读取本地静态私钥和对端公钥
+    /// 白名单：`KV_STATIC_KEY_FILE` 指向私钥文件，`KV_PEER_PUBLIC_KEY_ALLOWLIST`
+    /// 是逗号分隔的十六进制公钥列表。两者都不配置就和 `builder` 完全一样
+    /// （随机临时密钥、不校验对端），这是 server/client 两个二进制实际
+    /// 用来打开静态密钥、对端锁定这两个功能的入口。
+    pub fn builder_from_env(params: &'static str, initiator: bool) -> Result<Builder> {
+        let mut builder = Self::builder(params, initiator);
+
+        if let Ok(path) = std::env::var("KV_STATIC_KEY_FILE") {
+            builder = builder.local_private_key_file(path)?;
+        }
+
+        if let Ok(raw) = std::env::var("KV_PEER_PUBLIC_KEY_ALLOWLIST") {
+            let allowlist = raw
+                .split(',')
+                .map(|key| decode_hex(key.trim()))
+                .collect::<Result<Vec<_>>>()?;
+            builder = builder.remote_public_key_allowlist(allowlist);
+        }
+
+        Ok(builder)
+    }
+
+    pub fn switch_transport_mode(&mut self) -> Result<()> {
         self.state = match std::mem::replace(&mut self.state, NoiseState::None) {
-            NoiseState::Handshake(s) => NoiseState::Transport(s.into_transport_mode()?),
+            NoiseState::Handshake(s) => {
+                // 握手状态被消费之前，先取出对端出示的静态公钥，再核验它是否在
+                // 调用方配置的白名单里；不在名单内就拒绝握手，而不是默默信任
+                // 任何完成了 DH 的对端。
+                let remote_static = s.get_remote_static().map(<[u8]>::to_vec);
+                let transport = s.into_transport_mode()?;
+
+                if let Some(allowlist) = &self.remote_public_key_allowlist {
+                    let remote_static = remote_static
+                        .ok_or_else(|| anyhow::anyhow!("peer presented no static key to pin"))?;
+                    if !allowlist.iter().any(|key| key == &remote_static) {
+                        return Err(anyhow::anyhow!("remote static key is not in the allowlist"));
+                    }
+                }
+
+                NoiseState::Transport(transport)
+            }
             v => v,
         };
 
@@ -119,19 +187,50 @@ impl Builder {
     /// - `params`: Noise 协议参数字符串
     /// - `initiator`: 是否为发起方（true 为发起方，false 为响应方）
     fn new(params: &'static str, initiator: bool) -> Self {
-        Self { params, initiator }
+        Self {
+            params,
+            initiator,
+            local_private_key: None,
+            remote_public_key_allowlist: None,
+        }
+    }
+
+    /// 使用一份长期持有的本地静态私钥（原始字节），取代每次握手都随机生成
+    /// 一对临时密钥的默认行为，从而让同一个身份能在多次连接间被识别。
+    pub fn local_private_key(mut self, key: Vec<u8>) -> Self {
+        self.local_private_key = Some(key);
+        self
+    }
+
+    /// 从文件加载本地静态私钥，效果等同于 `local_private_key`。
+    pub fn local_private_key_file(self, path: impl AsRef<std::path::Path>) -> Result<Self> {
+        let key = std::fs::read(path)?;
+        Ok(self.local_private_key(key))
+    }
+
+    /// 设置可信对端静态公钥白名单。握手完成后，如果对端出示的静态公钥不在
+    /// 名单内，`handshake()` 会返回错误，由此将任意对端都能通过 DH 的默认
+    /// 信任关系，收紧为只信任被固定（pinned）过的客户端/服务端。
+    pub fn remote_public_key_allowlist(mut self, allowlist: Vec<Vec<u8>>) -> Self {
+        self.remote_public_key_allowlist = Some(allowlist);
+        self
     }
 
     /// 基于当前 Builder 构建一个 NoiseCodec 实例
     ///
     /// 该方法会根据 initiator 标志，创建握手状态的 Noise 协议对象，并封装为 NoiseCodec
     fn new_codec(self) -> Result<NoiseCodec> {
-        // 创建 snow 的 Builder，用于生成密钥对和协议状态
-        let builder = snow::Builder::new(self.params.parse()?);
-        // 生成本地密钥对
-        let keypair = builder.generate_keypair()?;
-        // 设置本地私钥
-        let builder = builder.local_private_key(&keypair.private);
+        let params: snow::params::NoiseParams = self.params.parse()?;
+
+        // 优先使用调用方持久化的静态私钥；没有配置的话退化为随机生成一份
+        // 临时密钥对，保持原来的行为不变。
+        let local_private_key = match &self.local_private_key {
+            Some(key) => key.clone(),
+            None => snow::Builder::new(params.clone()).generate_keypair()?.private,
+        };
+
+        // 创建 snow 的 Builder，用于构建协议状态
+        let builder = snow::Builder::new(params).local_private_key(&local_private_key);
         // 根据 initiator 标志，构建握手状态（发起方或响应方）
         let noise = match self.initiator {
             true => builder.build_initiator()?,
@@ -139,8 +238,11 @@ impl Builder {
         };
         // 返回 NoiseCodec，初始状态为握手阶段
         Ok(NoiseCodec {
+            remote_public_key_allowlist: self.remote_public_key_allowlist.clone(),
             builder: self,
             state: NoiseState::Handshake(Box::new(noise)),
+            reassembly_total_len: None,
+            reassembly_buf: BytesMut::new(),
         })
     }
 
@@ -161,32 +263,37 @@ impl Builder {
 }
 
 // 为 NoiseCodec 实现 Encoder trait，用于加密和编码要发送的数据帧
+//
+// 单个 Noise transport 消息最多只能携带 MAX_CHUNK_PLAINTEXT 字节的明文，
+// 所以这里把逻辑消息拆成若干个 chunk：先在明文前面加上一个 varint 编码的
+// 总长度头，再按 MAX_CHUNK_PLAINTEXT 切片，逐个加密并作为独立的、带 2
+// 字节长度前缀的 on-wire frame 写出，解码端据此重组出完整消息。
 impl Encoder<Bytes> for NoiseCodec {
     // 编码过程中可能出现的错误类型
     type Error = anyhow::Error;
 
     // encode 方法负责将明文数据加密后写入目标缓冲区
     fn encode(&mut self, item: Bytes, dst: &mut BytesMut) -> Result<(), Self::Error> {
-        // 创建一个临时缓冲区用于存放加密后的数据
-        let mut buf = [0u8; MAX_FRAME_SIZE];
-        // 获取待加密数据的长度
-        let n = item.len();
-
-        // 如果数据长度超过最大帧长度，返回错误
-        if n > MAX_FRAME_SIZE {
-            return Err(anyhow::anyhow!("Invalid Input".to_string()));
+        // 前置一个 varint 总长度头，解码端据此判断重组何时完成
+        let mut plaintext = BytesMut::with_capacity(10 + item.len());
+        prost::encoding::encode_varint(item.len() as u64, &mut plaintext);
+        plaintext.extend_from_slice(&item);
+
+        // 按 MAX_CHUNK_PLAINTEXT 切分，每个 chunk 各自加密、各自成帧
+        for chunk in plaintext.chunks(MAX_CHUNK_PLAINTEXT) {
+            // 创建一个临时缓冲区用于存放加密后的数据
+            let mut buf = [0u8; MAX_FRAME_SIZE];
+            // 使用 Noise 协议状态加密数据，n 为加密后数据的实际长度
+            let n = self.state.write_message(chunk, &mut buf)?;
+
+            // 预留空间：帧头（2字节）+ 加密后数据长度
+            dst.reserve(HEADER_LEN + n);
+            // 写入帧头（2字节，表示加密后数据长度）
+            dst.put_uint(n as u64, HEADER_LEN);
+            // 写入加密后的数据
+            dst.put_slice(&buf[..n]);
         }
 
-        // 使用 Noise 协议状态加密数据，n 为加密后数据的实际长度
-        let n = self.state.write_message(&item, &mut buf)?;
-
-        // 预留空间：帧头（2字节）+ 加密后数据长度
-        dst.reserve(HEADER_LEN + n);
-        // 写入帧头（2字节，表示加密后数据长度）
-        dst.put_uint(n as u64, HEADER_LEN);
-        // 写入加密后的数据
-        dst.put_slice(&buf[..n]);
-
         Ok(())
     }
 }
@@ -202,27 +309,57 @@ impl Decoder for NoiseCodec {
         // 创建一个临时缓冲区用于存放解密后的数据
         let mut buf = [0u8; MAX_FRAME_SIZE];
 
-        // 如果缓冲区中的数据长度小于头部长度（2字节），说明数据还不完整，返回 None 等待更多数据
-        if src.len() < HEADER_LEN {
-            return Ok(None);
-        }
+        // 逐帧解密；消息还没凑满时继续解 src 里已经到达的下一个 chunk，
+        // 只有 src 里没有完整的帧时才返回 None 等待更多数据。
+        loop {
+            // 如果缓冲区中的数据长度小于头部长度（2字节），说明数据还不完整，返回 None 等待更多数据
+            if src.len() < HEADER_LEN {
+                return Ok(None);
+            }
 
-        // 读取头部，获取数据帧的长度（前2字节），并将其转换为 usize
-        let len = src.get_uint(HEADER_LEN) as usize;
+            // 只窥视帧长度而不消费字节：如果消费后发现帧体还没收全，必须能把
+            // 头部原样留在 src 里等待下一次 poll，否则下次解码会少读 2 字节。
+            let mut header = &src[..HEADER_LEN];
+            let len = header.get_uint(HEADER_LEN) as usize;
 
-        // 如果剩余的数据长度小于帧长度，说明数据还未接收完整，返回 None
-        if src.len() < len {
-            return Ok(None);
-        }
+            // 如果剩余的数据长度小于帧长度，说明数据还未接收完整，返回 None
+            if src.len() < HEADER_LEN + len {
+                return Ok(None);
+            }
 
-        // 从缓冲区中取出完整的数据帧
-        let payload = src.split_to(len);
+            // 真正消费帧头，再取出完整的帧体
+            src.advance(HEADER_LEN);
+            let payload = src.split_to(len);
+
+            // 使用 Noise 协议状态解密数据帧，n 为解密后数据的实际长度；解密失败
+            // （比如被篡改的 chunk）直接通过 `?` 向上抛出错误，而不是悄悄截断。
+            let n = self.state.read_message(&payload, &mut buf)?;
+            self.reassembly_buf.extend_from_slice(&buf[..n]);
+
+            // 第一个（或前几个）chunk 的明文开头是 varint 总长度头，先把它解析
+            // 出来，再继续累积后续 chunk，直到凑满声明的总长度。
+            if self.reassembly_total_len.is_none() {
+                let mut header = &self.reassembly_buf[..];
+                let before = header.len();
+                match prost::encoding::decode_varint(&mut header) {
+                    Ok(total_len) => {
+                        let consumed = before - header.len();
+                        self.reassembly_buf.advance(consumed);
+                        self.reassembly_total_len = Some(total_len);
+                    }
+                    Err(_) => continue,
+                }
+            }
 
-        // 使用 Noise 协议状态解密数据帧，n 为解密后数据的实际长度
-        let n = self.state.read_message(&payload, &mut buf)?;
+            let total_len = self.reassembly_total_len.unwrap() as usize;
+            if self.reassembly_buf.len() < total_len {
+                continue;
+            }
 
-        // 返回解密后的数据，封装为 BytesMut
-        Ok(Some(BytesMut::from(&buf[..n])))
+            let item = self.reassembly_buf.split_to(total_len);
+            self.reassembly_total_len = None;
+            return Ok(Some(item));
+        }
     }
 }
 
@@ -272,4 +409,151 @@ mod tests {
 
         Ok(())
     }
+
+    // 跑完 Noise XX 的三次握手，返回一对已经进入 transport 模式的 codec。
+    fn handshake_pair() -> Result<(NoiseCodec, NoiseCodec)> {
+        handshake_pair_with(
+            NoiseCodec::builder(NOISE_PARAMS, true),
+            NoiseCodec::builder(NOISE_PARAMS, false),
+        )
+    }
+
+    fn handshake_pair_with(client_builder: Builder, server_builder: Builder) -> Result<(NoiseCodec, NoiseCodec)> {
+        let mut client = client_builder.new_codec()?;
+        let mut server = server_builder.new_codec()?;
+        let mut buf = BytesMut::new();
+
+        client.encode(Bytes::new(), &mut buf)?;
+        let mut msg = buf.split_to(buf.len());
+        let msg = server.decode(&mut msg)?.unwrap();
+
+        server.encode(msg.freeze(), &mut buf)?;
+        let mut msg = buf.split_to(buf.len());
+        let msg = client.decode(&mut msg)?.unwrap();
+
+        client.encode(msg.freeze(), &mut buf)?;
+        let mut msg = buf.split_to(buf.len());
+        server.decode(&mut msg)?.unwrap();
+
+        client.switch_transport_mode()?;
+        server.switch_transport_mode()?;
+
+        Ok((client, server))
+    }
+
+    #[test]
+    fn fragments_large_payload_across_frames() -> Result<()> {
+        let (mut client, mut server) = handshake_pair()?;
+
+        // 足够大，必然被拆成多个 Noise frame
+        let payload = vec![0xABu8; MAX_CHUNK_PLAINTEXT * 3 + 1234];
+
+        let mut buf = BytesMut::new();
+        client.encode(Bytes::from(payload.clone()), &mut buf)?;
+
+        // decode() 在收齐所有 chunk 之前应该一直返回 None
+        let mut received = None;
+        while !buf.is_empty() {
+            if let Some(item) = server.decode(&mut buf)? {
+                received = Some(item);
+            }
+        }
+
+        assert_eq!(received.unwrap().freeze().as_ref(), payload.as_slice());
+        Ok(())
+    }
+
+    #[test]
+    fn zero_length_message_round_trips() -> Result<()> {
+        let (mut client, mut server) = handshake_pair()?;
+
+        let mut buf = BytesMut::new();
+        client.encode(Bytes::new(), &mut buf)?;
+
+        let received = server.decode(&mut buf)?.unwrap();
+        assert!(received.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn corrupted_chunk_surfaces_as_error_instead_of_truncating() -> Result<()> {
+        let (mut client, mut server) = handshake_pair()?;
+
+        let mut buf = BytesMut::new();
+        client.encode(Bytes::from_static(b"hello"), &mut buf)?;
+
+        // 篡改加密后的帧体，使 AEAD 校验失败
+        let last = buf.len() - 1;
+        buf[last] ^= 0xFF;
+
+        assert!(server.decode(&mut buf).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn accepts_peer_pinned_in_allowlist() -> Result<()> {
+        let server_keypair = snow::Builder::new(NOISE_PARAMS.parse()?).generate_keypair()?;
+
+        let client = NoiseCodec::builder(NOISE_PARAMS, true)
+            .remote_public_key_allowlist(vec![server_keypair.public.clone()]);
+        let server = NoiseCodec::builder(NOISE_PARAMS, false)
+            .local_private_key(server_keypair.private);
+
+        assert!(handshake_pair_with(client, server).is_ok());
+        Ok(())
+    }
+
+    #[test]
+    fn rejects_peer_not_in_allowlist() -> Result<()> {
+        let server_keypair = snow::Builder::new(NOISE_PARAMS.parse()?).generate_keypair()?;
+        let some_other_key = snow::Builder::new(NOISE_PARAMS.parse()?)
+            .generate_keypair()?
+            .public;
+
+        let client =
+            NoiseCodec::builder(NOISE_PARAMS, true).remote_public_key_allowlist(vec![some_other_key]);
+        let server = NoiseCodec::builder(NOISE_PARAMS, false).local_private_key(server_keypair.private);
+
+        assert!(handshake_pair_with(client, server).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn builder_from_env_loads_key_file_and_allowlist() -> Result<()> {
+        let keypair = snow::Builder::new(NOISE_PARAMS.parse()?).generate_keypair()?;
+
+        let key_path = std::env::temp_dir().join(format!(
+            "kv-noise-static-key-test-{}-{}",
+            std::process::id(),
+            std::thread::current().name().unwrap_or("main")
+        ));
+        std::fs::write(&key_path, &keypair.private)?;
+
+        // SAFETY: no other test reads/writes these two vars.
+        unsafe {
+            std::env::set_var("KV_STATIC_KEY_FILE", &key_path);
+            std::env::set_var(
+                "KV_PEER_PUBLIC_KEY_ALLOWLIST",
+                hex_encode(&keypair.public),
+            );
+        }
+
+        let builder = NoiseCodec::builder_from_env(NOISE_PARAMS, false)?;
+        assert_eq!(builder.local_private_key, Some(keypair.private));
+        assert_eq!(
+            builder.remote_public_key_allowlist,
+            Some(vec![keypair.public])
+        );
+
+        unsafe {
+            std::env::remove_var("KV_STATIC_KEY_FILE");
+            std::env::remove_var("KV_PEER_PUBLIC_KEY_ALLOWLIST");
+        }
+        std::fs::remove_file(&key_path)?;
+        Ok(())
+    }
+
+    fn hex_encode(bytes: &[u8]) -> String {
+        bytes.iter().map(|b| format!("{b:02x}")).collect()
+    }
 }
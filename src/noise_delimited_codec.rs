@@ -0,0 +1,79 @@
+use std::marker::PhantomData;
+
+use anyhow::Result;
+use bytes::BytesMut;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_util::codec::{Decoder, Encoder, Framed, FramedParts};
+
+use crate::noise_codec::{Builder as NoiseBuilder, NoiseCodec, NoiseStream};
+
+/// 在加密的 [`NoiseCodec`] 之上叠加一层 protobuf 编解码：`decode` 产出 `Out`，
+/// `encode` 接受 `In`，二者都是 [`prost::Message`]。这样 `Framed<TcpStream, _>`
+/// 直接吞吐带类型的业务消息，而不是裸的密文 `Bytes`。
+pub struct NoiseDelimitedCodec<In, Out> {
+    inner: NoiseCodec,
+    _marker: PhantomData<fn(In) -> Out>,
+}
+
+impl<In, Out> NoiseDelimitedCodec<In, Out> {
+    fn new(inner: NoiseCodec) -> Self {
+        Self {
+            inner,
+            _marker: PhantomData,
+        }
+    }
+}
+
+impl<In, Out> NoiseDelimitedCodec<In, Out>
+where
+    In: prost::Message,
+{
+    /// 在 `io` 上跑完 Noise XX 握手，然后把已经进入 transport 模式的 codec
+    /// 包装成 `NoiseDelimitedCodec`，使同一条连接之后只收发 `In`/`Out` 两种
+    /// 类型化消息。
+    pub async fn handshake_framed<T>(builder: NoiseBuilder, io: T) -> Result<Framed<T, Self>>
+    where
+        T: AsyncRead + AsyncWrite + Send + Sync + Unpin,
+    {
+        let mut framed = builder.new_framed(io)?;
+        framed.handshake().await?;
+
+        // 握手已经在原始的 Framed<T, NoiseCodec> 上完成，取出其 IO 和
+        // (已切换到 transport 模式的) codec，换上带类型的 codec 重新组装，
+        // 同时把尚未消费的读写缓冲区原样带过去。
+        let parts = framed.into_parts();
+        let mut new_parts = FramedParts::new::<In>(parts.io, Self::new(parts.codec));
+        new_parts.read_buf = parts.read_buf;
+        new_parts.write_buf = parts.write_buf;
+
+        Ok(Framed::from_parts(new_parts))
+    }
+}
+
+impl<In, Out> Decoder for NoiseDelimitedCodec<In, Out>
+where
+    Out: prost::Message + Default,
+{
+    type Item = Out;
+    type Error = anyhow::Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        match self.inner.decode(src)? {
+            Some(buf) => Ok(Some(Out::decode(buf)?)),
+            None => Ok(None),
+        }
+    }
+}
+
+impl<In, Out> Encoder<In> for NoiseDelimitedCodec<In, Out>
+where
+    In: prost::Message,
+{
+    type Error = anyhow::Error;
+
+    fn encode(&mut self, item: In, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        let mut buf = BytesMut::with_capacity(item.encoded_len());
+        item.encode(&mut buf)?;
+        self.inner.encode(buf.freeze(), dst)
+    }
+}
@@ -0,0 +1,50 @@
+// This file is @generated by prost-build.
+/// A request sent from client to server.
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Request {
+    #[prost(oneof = "request::Command", tags = "1, 2, 3")]
+    pub command: ::core::option::Option<request::Command>,
+}
+/// Nested message and enum types in `Request`.
+pub mod request {
+    #[derive(Clone, PartialEq, ::prost::Oneof)]
+    pub enum Command {
+        #[prost(message, tag = "1")]
+        Get(super::RequestGet),
+        #[prost(message, tag = "2")]
+        Put(super::RequestPut),
+        #[prost(message, tag = "3")]
+        Subscribe(super::RequestSubscribe),
+    }
+}
+/// Get the value stored under `key`.
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct RequestGet {
+    #[prost(string, tag = "1")]
+    pub key: ::prost::alloc::string::String,
+}
+/// Store `value` under `key`.
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct RequestPut {
+    #[prost(string, tag = "1")]
+    pub key: ::prost::alloc::string::String,
+    #[prost(bytes = "vec", tag = "2")]
+    pub value: ::prost::alloc::vec::Vec<u8>,
+}
+/// Watch every key starting with `key_prefix`: the server streams back a
+/// Response each time a matching Put happens, until the connection closes.
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct RequestSubscribe {
+    #[prost(string, tag = "1")]
+    pub key_prefix: ::prost::alloc::string::String,
+}
+/// The server's reply to a Request.
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Response {
+    #[prost(uint32, tag = "1")]
+    pub code: u32,
+    #[prost(string, tag = "2")]
+    pub key: ::prost::alloc::string::String,
+    #[prost(bytes = "vec", tag = "3")]
+    pub value: ::prost::alloc::vec::Vec<u8>,
+}
@@ -20,6 +20,14 @@ impl Response {
             value: Default::default(),
         }
     }
+    // 连接已经在订阅状态下又发来了一条别的请求（Get/Put/再次 Subscribe）
+    pub fn already_subscribed(key: String) -> Self {
+        Response {
+            code: 409,
+            key,
+            value: Default::default(),
+        }
+    }
 }
 
 impl TryFrom<BytesMut> for Response {
@@ -69,4 +77,11 @@ impl Request {
             })),
         }
     }
+    pub fn new_subscribe(key_prefix: &str) -> Self {
+        Request {
+            command: Some(request::Command::Subscribe(RequestSubscribe {
+                key_prefix: key_prefix.to_string(),
+            })),
+        }
+    }
 }
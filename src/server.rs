@@ -1,92 +1,337 @@
+mod noise_codec;
+mod noise_delimited_codec;
 mod pb;
+mod storage;
 use std::sync::Arc;
 
 use anyhow::{Error, Result};
 use dashmap::DashMap;
 
 use futures::{SinkExt, StreamExt};
+use noise_codec::{NOISE_PARAMS, NoiseCodec};
+use noise_delimited_codec::NoiseDelimitedCodec;
+use storage::{LogStorage, Storage};
 use tokio::net::TcpListener;
-use tokio_util::codec::LengthDelimitedCodec;
+use tokio::sync::{broadcast, mpsc};
 use tracing::info;
 
 use pb::{request::*, *};
+
+// 每个订阅 channel 的缓冲容量；跟不上的订阅者会丢失旧消息（Lagged），而不是
+// 拖慢 Put 的发布者。
+const SUBSCRIBE_CHANNEL_CAPACITY: usize = 16;
+
 #[derive(Debug)]
-struct ServerState {
-    store: DashMap<String, Vec<u8>>,
+struct ServerState<S> {
+    store: S,
+    // 按 key 前缀注册的订阅者：Put 发布时，找出所有前缀匹配的 channel 广播。
+    subscriptions: DashMap<String, broadcast::Sender<Response>>,
 }
 
-impl ServerState {
-    fn new() -> Self {
+impl<S: Storage> ServerState<S> {
+    fn new(store: S) -> Self {
         ServerState {
-            store: DashMap::new(),
+            store,
+            subscriptions: DashMap::new(),
         }
     }
 }
 
-impl Default for ServerState {
-    fn default() -> Self {
-        ServerState::new()
-    }
-}
-#[tokio::main]
-async fn main() -> Result<(), Error> {
-    tracing_subscriber::fmt::init();
-    let number_of_yaks = 3;
-    // this creates a new event, outside of any spans.
-    info!(number_of_yaks, "xx");
+// 处理单条已完成 Noise 握手的连接，直到对端断开。拆成独立函数而不是内联在
+// `main` 的 `tokio::spawn` 里，这样测试能直接拿一对内存 socket 驱动它，
+// 不用真的起一个 TcpListener。
+async fn handle_connection<S, T>(
+    shared: Arc<ServerState<S>>,
+    noise_builder: noise_codec::Builder,
+    stream: T,
+) -> Result<(), Error>
+where
+    S: Storage + 'static,
+    T: tokio::io::AsyncRead + tokio::io::AsyncWrite + Send + Sync + Unpin + 'static,
+{
+    // 作为 responder 完成 Noise XX 握手，然后在加密通道上直接收发
+    // 类型化的 Request/Response，而不是裸字节帧。
+    let stream =
+        NoiseDelimitedCodec::<Response, Request>::handshake_framed(noise_builder, stream).await?;
+    // 把 Framed 拆成独立的读/写两半：读半只管尽快把下一帧解码出来，
+    // 不会被响应的发送卡住，从而消除之前严格串行的
+    // “读一条、处理完、发一条”带来的队头阻塞。
+    let (sink, mut read) = stream.split();
+    let (resp_tx, mut resp_rx) = mpsc::unbounded_channel::<Response>();
 
-    let state = Arc::new(ServerState::new());
-    let addr = "0.0.0.0:8888";
-    let listener = TcpListener::bind(addr).await?;
-    info!("Listening on {}", addr);
-    // 在主线程中执行 accept
-    loop {
-        let (stream, addr) = listener.accept().await?;
-        info!("Accepted connection from {}", addr);
+    // 独立的写任务：从 channel 里取出响应按到达顺序写回 sink。
+    // 读半和下面的 worker 任务只需要把响应丢进 channel 就不用等 IO。
+    tokio::spawn(async move {
+        let mut sink = sink;
+        while let Some(response) = resp_rx.recv().await {
+            if sink.send(response).await.is_err() {
+                break;
+            }
+        }
+    });
 
-        let shared = state.clone();
-        // 生成一个task 处理连接
-        // 我们要怎么传递消息,TCP 并不知道消息有多长
+    // 单独一个 worker 任务按到达顺序串行处理 Get/Put，保证同一条连接
+    // 上先发的 Put 一定先于后发的 Get 被应用到 store：如果改成每条
+    // 请求各起一个 task 并发跑 store 操作，完成顺序就变成由调度器
+    // 决定而不是发送顺序，同连接内的 read-after-write 就不再成立。
+    // 读半继续把后续帧解码进这个队列，不会被还在处理中的请求卡住。
+    let (work_tx, mut work_rx) = mpsc::unbounded_channel::<Request>();
+    {
+        let shared = shared.clone();
+        let resp_tx = resp_tx.clone();
         tokio::spawn(async move {
-            #[allow(unused_doc_comments)]
-            /// 使用2字节长度字段将提供的 `stream` 包装为 `LengthDelimitedCodec`，
-            /// 使该流能够以带有长度前缀的帧进行发送和接收。
-            /// 这样可以安全高效地在流上传递消息并检测消息边界。
-            let mut stream = LengthDelimitedCodec::builder()
-                .length_field_length(2)
-                .new_framed(stream);
-            // steam.next 实现了读取长度字段的帧
-            // 持续读取客户端发送过来的每一帧数据
-            while let Some(Ok(buf)) = stream.next().await {
-                // 尝试将收到的字节缓冲区反序列化为 Request 消息
-                let msg: Request = buf.try_into()?;
-                info!("Got a command {:?}", msg);
-
+            while let Some(msg) = work_rx.recv().await {
                 // 根据请求中的 command 字段进行匹配处理
                 let response = match msg.command {
                     // 处理 Get 命令
                     Some(Command::Get(RequestGet { key })) => match shared.store.get(&key) {
                         // 如果 key 存在，返回对应的值
-                        Some(v) => Response::new(key, v.value().to_vec()),
+                        Some(value) => Response::new(key, value),
                         // 如果 key 不存在，返回 not_found 响应
                         None => Response::not_found(key),
                     },
                     // 处理 Put 命令
                     Some(Command::Put(RequestPut { key, value })) => {
-                        // 将 key 和 value 插入到共享的存储中
-                        shared.store.insert(key.clone(), value.clone());
+                        // 将 key 和 value 写入共享的存储
+                        shared.store.put(key.clone(), value.clone());
+                        // 通知所有前缀匹配这个 key 的订阅者
+                        for entry in shared.subscriptions.iter() {
+                            if key.starts_with(entry.key().as_str()) {
+                                let _ = entry
+                                    .value()
+                                    .send(Response::new(key.clone(), value.clone()));
+                            }
+                        }
                         // 返回插入成功的响应
                         Response::new(key, value)
                     }
+                    Some(Command::Subscribe(_)) => unreachable!("handled in the read loop"),
                     // 未知命令，暂未实现
                     None => unimplemented!("No command"),
                 };
 
-                // 将响应序列化后发送回客户端
-                stream.send(response.into()).await?;
+                // 发送响应给客户端；写任务已经退出就说明连接没了，忽略即可
+                if resp_tx.send(response).is_err() {
+                    break;
+                }
             }
-            // 任务正常结束，返回 Ok
-            Ok::<(), Error>(())
         });
     }
+
+    // 持续读取客户端发送过来的每一条 Request
+    while let Some(Ok(msg)) = read.next().await {
+        info!("Got a command {:?}", msg);
+
+        if let Some(Command::Subscribe(RequestSubscribe { key_prefix })) = msg.command {
+            // Subscribe 不产出单个 Response，而是让这条连接接下来只
+            // 转发匹配的 Put 通知，直到客户端断开，所以单独处理，
+            // 不走下面“一条请求对一条响应”的通用分支。
+            let mut updates = shared
+                .subscriptions
+                .entry(key_prefix.clone())
+                .or_insert_with(|| broadcast::channel(SUBSCRIBE_CHANNEL_CAPACITY).0)
+                .subscribe();
+
+            loop {
+                tokio::select! {
+                    update = updates.recv() => match update {
+                        Ok(resp) => {
+                            if resp_tx.send(resp).is_err() {
+                                break;
+                            }
+                        }
+                        Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                        Err(broadcast::error::RecvError::Closed) => break,
+                    },
+                    frame = read.next() => match frame {
+                        None => break,
+                        Some(Err(_)) => break,
+                        // 已经在订阅这条连接又发来了别的请求（Get/Put/再次
+                        // Subscribe）：不能像以前那样悄悄吞掉，否则对端会
+                        // 一直等一个永远不会到来的响应而挂死；回一个明确
+                        // 的错误 Response，连接继续保持订阅状态。
+                        Some(Ok(other)) => {
+                            let key = request_key(&other).unwrap_or_default();
+                            if resp_tx.send(Response::already_subscribed(key)).is_err() {
+                                break;
+                            }
+                        }
+                    },
+                }
+            }
+
+            // Unsubscribe：丢弃自己的 receiver，如果这是该前缀最后一个
+            // 订阅者，就把整个 channel 从注册表里清掉，避免泄漏。
+            drop(updates);
+            shared
+                .subscriptions
+                .remove_if(&key_prefix, |_, tx| tx.receiver_count() == 0);
+            break;
+        }
+
+        // 交给上面的 worker 按到达顺序串行处理；读半继续往下解码，
+        // 不会被这条请求的处理卡住，流水线化的是解码和响应发送，
+        // store 操作本身仍然严格按发送顺序完成。
+        if work_tx.send(msg).is_err() {
+            break;
+        }
+    }
+
+    Ok(())
+}
+
+// 从一条 Request 里取出它的 key，只用来给“已订阅连接上收到的杂项请求”生成
+// 错误响应的 key 字段，不区分具体是哪种 command。
+fn request_key(req: &Request) -> Option<String> {
+    match &req.command {
+        Some(Command::Get(RequestGet { key })) => Some(key.clone()),
+        Some(Command::Put(RequestPut { key, .. })) => Some(key.clone()),
+        Some(Command::Subscribe(RequestSubscribe { key_prefix })) => Some(key_prefix.clone()),
+        None => None,
+    }
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Error> {
+    tracing_subscriber::fmt::init();
+    let number_of_yaks = 3;
+    // this creates a new event, outside of any spans.
+    info!(number_of_yaks, "xx");
+
+    // 用追加写日志持久化存储，这样服务重启后数据还在；换成 `MemoryStorage::new()`
+    // 就能退回纯内存、重启即丢的行为。
+    let state = Arc::new(ServerState::new(LogStorage::open("kv-data.log")?));
+    // 从环境变量里读取可选的长期静态私钥（`KV_STATIC_KEY_FILE`）和可信客户端
+    // 公钥白名单（`KV_PEER_PUBLIC_KEY_ALLOWLIST`），没配置就退回随机临时
+    // 密钥、不校验对端的旧行为。
+    let noise_builder = NoiseCodec::builder_from_env(NOISE_PARAMS, false)?;
+    let addr = "0.0.0.0:8888";
+    let listener = TcpListener::bind(addr).await?;
+    info!("Listening on {}", addr);
+    // 在主线程中执行 accept
+    loop {
+        let (stream, addr) = listener.accept().await?;
+        info!("Accepted connection from {}", addr);
+
+        let shared = state.clone();
+        let noise_builder = noise_builder.clone();
+        // 生成一个task 处理连接
+        tokio::spawn(handle_connection(shared, noise_builder, stream));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use storage::MemoryStorage;
+    use tokio::io::duplex;
+
+    // 同一条连接上先 Put 再 Get 同一个 key，Get 必须看到 Put 写入的值：
+    // 如果 worker 把每条请求各自 spawn 成独立 task 并发跑 store 操作，
+    // 完成顺序会变成由调度器决定，这个断言会随机失败。
+    #[tokio::test]
+    async fn put_then_get_on_same_connection_is_ordered() {
+        let state = Arc::new(ServerState::new(MemoryStorage::new()));
+        let (server_io, client_io) = duplex(64 * 1024);
+
+        tokio::spawn(handle_connection(state, NoiseCodec::builder(NOISE_PARAMS, false), server_io));
+
+        let mut client = NoiseDelimitedCodec::<Request, Response>::handshake_framed(
+            NoiseCodec::builder(NOISE_PARAMS, true),
+            client_io,
+        )
+        .await
+        .unwrap();
+
+        client.send(Request::new_put("hello", b"world")).await.unwrap();
+        client.send(Request::new_get("hello")).await.unwrap();
+
+        let put_resp = client.next().await.unwrap().unwrap();
+        assert_eq!(put_resp.code, 0);
+
+        let get_resp = client.next().await.unwrap().unwrap();
+        assert_eq!(get_resp.code, 0);
+        assert_eq!(get_resp.value, b"world".to_vec());
+    }
+
+    // 订阅之后在同一条连接上再发一条 Put，之前会被静默丢弃，客户端会一直
+    // 等一个永远不会来的响应；现在应该收到一个明确的错误 Response。
+    #[tokio::test]
+    async fn request_after_subscribe_gets_an_error_response() {
+        let state = Arc::new(ServerState::new(MemoryStorage::new()));
+        let (server_io, client_io) = duplex(64 * 1024);
+
+        tokio::spawn(handle_connection(state, NoiseCodec::builder(NOISE_PARAMS, false), server_io));
+
+        let mut client = NoiseDelimitedCodec::<Request, Response>::handshake_framed(
+            NoiseCodec::builder(NOISE_PARAMS, true),
+            client_io,
+        )
+        .await
+        .unwrap();
+
+        client.send(Request::new_subscribe("hello")).await.unwrap();
+        client.send(Request::new_put("hello", b"world")).await.unwrap();
+
+        let resp = client.next().await.unwrap().unwrap();
+        assert_eq!(resp.code, 409);
+        assert_eq!(resp.key, "hello");
+    }
+
+    // 一条连接订阅某个前缀后，另一条连接对匹配 key 的 Put 必须被转发给订阅者。
+    #[tokio::test]
+    async fn subscriber_receives_notification_on_matching_put() {
+        let state = Arc::new(ServerState::new(MemoryStorage::new()));
+
+        let (sub_server_io, sub_client_io) = duplex(64 * 1024);
+        tokio::spawn(handle_connection(
+            state.clone(),
+            NoiseCodec::builder(NOISE_PARAMS, false),
+            sub_server_io,
+        ));
+        let mut subscriber = NoiseDelimitedCodec::<Request, Response>::handshake_framed(
+            NoiseCodec::builder(NOISE_PARAMS, true),
+            sub_client_io,
+        )
+        .await
+        .unwrap();
+        subscriber.send(Request::new_subscribe("hello")).await.unwrap();
+
+        let (pub_server_io, pub_client_io) = duplex(64 * 1024);
+        tokio::spawn(handle_connection(
+            state,
+            NoiseCodec::builder(NOISE_PARAMS, false),
+            pub_server_io,
+        ));
+        let mut publisher = NoiseDelimitedCodec::<Request, Response>::handshake_framed(
+            NoiseCodec::builder(NOISE_PARAMS, true),
+            pub_client_io,
+        )
+        .await
+        .unwrap();
+
+        // 订阅的注册发生在订阅连接自己的任务里，和这里发布者的握手是并发的，
+        // 所以重复 Put 直到订阅者收到通知，而不是假设一次 Put 就一定能赶上
+        // 注册完成的时间点。
+        let mut notification = None;
+        for _ in 0..50 {
+            publisher
+                .send(Request::new_put("hello", b"world"))
+                .await
+                .unwrap();
+            let put_resp = publisher.next().await.unwrap().unwrap();
+            assert_eq!(put_resp.code, 0);
+
+            if let Ok(Some(Ok(resp))) =
+                tokio::time::timeout(std::time::Duration::from_millis(20), subscriber.next()).await
+            {
+                notification = Some(resp);
+                break;
+            }
+        }
+
+        let notification = notification.expect("subscriber never received a notification");
+        assert_eq!(notification.key, "hello");
+        assert_eq!(notification.value, b"world".to_vec());
+    }
 }
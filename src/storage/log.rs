@@ -0,0 +1,257 @@
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::path::Path;
+use std::sync::Mutex;
+
+use super::{MemoryStorage, Storage};
+
+const TAG_PUT: u8 = 0;
+const TAG_DEL: u8 = 1;
+
+/// 追加写日志文件持久化的存储：每次 `put`/`del` 都先写一条记录到磁盘再更新
+/// 内存索引，启动时把整个日志重放回内存，从而让 KV 服务能扛得住重启。
+#[derive(Debug)]
+pub struct LogStorage {
+    memory: MemoryStorage,
+    log: Mutex<File>,
+}
+
+impl LogStorage {
+    pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        let mut file = OpenOptions::new()
+            .create(true)
+            .read(true)
+            .append(true)
+            .open(path)?;
+
+        let memory = MemoryStorage::new();
+        replay(&mut file, &memory)?;
+
+        Ok(Self {
+            memory,
+            log: Mutex::new(file),
+        })
+    }
+
+    // 在持有 `log` 锁的同时把记录写到磁盘并把 `apply` 应用到内存索引上，保证
+    // 磁盘落盘顺序和内存更新顺序严格一致：如果两步用各自的锁（或者压根不加
+    // 锁），并发写同一个 key 时日志里的顺序和内存里最终生效的顺序可能不一
+    // 致，崩溃重启后恢复出来的值就可能不是崩溃前真正生效的那个值。
+    fn append_and_apply<T>(
+        &self,
+        record: &[u8],
+        apply: impl FnOnce(&MemoryStorage) -> T,
+    ) -> io::Result<T> {
+        let mut file = self.log.lock().unwrap();
+        file.write_all(record)?;
+        file.flush()?;
+        Ok(apply(&self.memory))
+    }
+}
+
+// 重放日志时，`cursor.len()` 之差就是这条记录实际消耗掉的字节数，用来在
+// 遇到尾部截断记录时知道该把文件截到哪。
+fn read_record(cursor: &mut &[u8], memory: &MemoryStorage) -> io::Result<()> {
+    let tag = read_u8(cursor)?;
+    let key = read_bytes(cursor)?;
+    let key = String::from_utf8(key).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+
+    match tag {
+        TAG_PUT => {
+            let value = read_bytes(cursor)?;
+            memory.put(key, value);
+        }
+        TAG_DEL => {
+            memory.del(&key);
+        }
+        other => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("unknown log record tag {other}"),
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+// 崩溃可能发生在某条记录的 `write_all` 中途，留下一条不完整的尾部记录。
+// 这和任何 WAL 一样，是预期会发生的情况，不是损坏：重放到该记录为止，
+// 把文件截断掉那段不完整的尾巴，而不是让 `open` 整个失败，导致服务器
+// 永远起不来。
+fn replay(file: &mut File, memory: &MemoryStorage) -> io::Result<()> {
+    let mut contents = Vec::new();
+    file.read_to_end(&mut contents)?;
+
+    let mut cursor = &contents[..];
+    let mut good_len = 0;
+    while !cursor.is_empty() {
+        match read_record(&mut cursor, memory) {
+            Ok(()) => good_len = contents.len() - cursor.len(),
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => break,
+            Err(e) => return Err(e),
+        }
+    }
+
+    if good_len < contents.len() {
+        file.set_len(good_len as u64)?;
+    }
+
+    Ok(())
+}
+
+fn read_u8(cursor: &mut &[u8]) -> io::Result<u8> {
+    if cursor.is_empty() {
+        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "truncated log record"));
+    }
+    let byte = cursor[0];
+    *cursor = &cursor[1..];
+    Ok(byte)
+}
+
+fn read_bytes(cursor: &mut &[u8]) -> io::Result<Vec<u8>> {
+    if cursor.len() < 4 {
+        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "truncated log record"));
+    }
+    let len = u32::from_be_bytes(cursor[..4].try_into().unwrap()) as usize;
+    *cursor = &cursor[4..];
+
+    if cursor.len() < len {
+        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "truncated log record"));
+    }
+    let bytes = cursor[..len].to_vec();
+    *cursor = &cursor[len..];
+    Ok(bytes)
+}
+
+fn encode_put(key: &str, value: &[u8]) -> Vec<u8> {
+    let mut record = Vec::with_capacity(1 + 4 + key.len() + 4 + value.len());
+    record.push(TAG_PUT);
+    record.extend_from_slice(&(key.len() as u32).to_be_bytes());
+    record.extend_from_slice(key.as_bytes());
+    record.extend_from_slice(&(value.len() as u32).to_be_bytes());
+    record.extend_from_slice(value);
+    record
+}
+
+fn encode_del(key: &str) -> Vec<u8> {
+    let mut record = Vec::with_capacity(1 + 4 + key.len());
+    record.push(TAG_DEL);
+    record.extend_from_slice(&(key.len() as u32).to_be_bytes());
+    record.extend_from_slice(key.as_bytes());
+    record
+}
+
+impl Storage for LogStorage {
+    fn get(&self, key: &str) -> Option<Vec<u8>> {
+        self.memory.get(key)
+    }
+
+    fn put(&self, key: String, value: Vec<u8>) -> Option<Vec<u8>> {
+        let record = encode_put(&key, &value);
+        self.append_and_apply(&record, move |memory| memory.put(key, value))
+            .expect("append-only log write failed")
+    }
+
+    fn del(&self, key: &str) -> Option<Vec<u8>> {
+        let record = encode_del(key);
+        self.append_and_apply(&record, |memory| memory.del(key))
+            .expect("append-only log write failed")
+    }
+
+    fn contains(&self, key: &str) -> bool {
+        self.memory.contains(key)
+    }
+
+    fn scan_prefix(&self, prefix: &str) -> Vec<(String, Vec<u8>)> {
+        self.memory.scan_prefix(prefix)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn replays_log_on_reopen() {
+        let path = std::env::temp_dir().join(format!("kv-log-storage-test-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        {
+            let storage = LogStorage::open(&path).unwrap();
+            storage.put("hello".to_string(), b"world".to_vec());
+            storage.put("foo".to_string(), b"bar".to_vec());
+            storage.del("foo");
+        }
+
+        let reopened = LogStorage::open(&path).unwrap();
+        assert_eq!(reopened.get("hello"), Some(b"world".to_vec()));
+        assert_eq!(reopened.get("foo"), None);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn tolerates_truncated_trailing_record() {
+        let path = std::env::temp_dir().join(format!("kv-log-storage-test-trunc-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        {
+            let storage = LogStorage::open(&path).unwrap();
+            storage.put("hello".to_string(), b"world".to_vec());
+        }
+
+        // Simulate a crash mid-`write_all`: append a partial record (tag +
+        // key length prefix, but no key/value bytes).
+        {
+            use std::io::Write;
+            let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+            file.write_all(&[TAG_PUT, 0, 0, 0, 5]).unwrap();
+        }
+
+        let reopened = LogStorage::open(&path).unwrap();
+        assert_eq!(reopened.get("hello"), Some(b"world".to_vec()));
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn concurrent_puts_to_same_key_keep_log_and_memory_consistent() {
+        let path = std::env::temp_dir().join(format!(
+            "kv-log-storage-test-concurrent-{}",
+            std::process::id()
+        ));
+        let _ = std::fs::remove_file(&path);
+
+        let storage = std::sync::Arc::new(LogStorage::open(&path).unwrap());
+        let handles: Vec<_> = (0..8)
+            .map(|thread| {
+                let storage = storage.clone();
+                std::thread::spawn(move || {
+                    for round in 0..200 {
+                        storage.put(
+                            "race-key".to_string(),
+                            format!("round{round}-thread{thread}").into_bytes(),
+                        );
+                    }
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+
+        // 重放磁盘上的日志得到的值必须和内存里最终生效的值完全一致：append
+        // 和内存更新如果不在同一把锁里完成，两者在并发写同一个 key 时可能
+        // 落地成不同的顺序。
+        let live_value = storage.get("race-key");
+
+        let mut file = OpenOptions::new().read(true).open(&path).unwrap();
+        let replayed = MemoryStorage::new();
+        replay(&mut file, &replayed).unwrap();
+
+        assert_eq!(replayed.get("race-key"), live_value);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
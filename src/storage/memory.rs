@@ -0,0 +1,41 @@
+use dashmap::DashMap;
+
+use super::Storage;
+
+/// 进程内存储，重启即丢失数据；是 `ServerState` 原来硬编码的行为。
+#[derive(Debug, Default)]
+pub struct MemoryStorage {
+    map: DashMap<String, Vec<u8>>,
+}
+
+impl MemoryStorage {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl Storage for MemoryStorage {
+    fn get(&self, key: &str) -> Option<Vec<u8>> {
+        self.map.get(key).map(|v| v.value().clone())
+    }
+
+    fn put(&self, key: String, value: Vec<u8>) -> Option<Vec<u8>> {
+        self.map.insert(key, value)
+    }
+
+    fn del(&self, key: &str) -> Option<Vec<u8>> {
+        self.map.remove(key).map(|(_, v)| v)
+    }
+
+    fn contains(&self, key: &str) -> bool {
+        self.map.contains_key(key)
+    }
+
+    fn scan_prefix(&self, prefix: &str) -> Vec<(String, Vec<u8>)> {
+        self.map
+            .iter()
+            .filter(|entry| entry.key().starts_with(prefix))
+            .map(|entry| (entry.key().clone(), entry.value().clone()))
+            .collect()
+    }
+}
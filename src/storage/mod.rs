@@ -0,0 +1,20 @@
+mod log;
+mod memory;
+
+pub use log::LogStorage;
+pub use memory::MemoryStorage;
+
+/// 服务端存储后端的扩展点。`ServerState` 只依赖这个 trait，因此换一个实现
+/// （内存、持久化、甚至远端存储）都不需要改动请求分发逻辑。
+pub trait Storage: Send + Sync {
+    fn get(&self, key: &str) -> Option<Vec<u8>>;
+    /// 写入 `key`/`value`，返回该 key 原来的值（如果存在）。
+    fn put(&self, key: String, value: Vec<u8>) -> Option<Vec<u8>>;
+    /// 删除 `key`，返回被删除的值（如果存在）。
+    fn del(&self, key: &str) -> Option<Vec<u8>>;
+    #[allow(dead_code)]
+    fn contains(&self, key: &str) -> bool;
+    /// 返回所有 key 以 `prefix` 开头的条目。
+    #[allow(dead_code)]
+    fn scan_prefix(&self, prefix: &str) -> Vec<(String, Vec<u8>)>;
+}